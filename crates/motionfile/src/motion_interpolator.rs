@@ -1,28 +1,203 @@
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::fmt::Debug;
+use std::ops::{Add, Mul, Sub};
 use std::time::Duration;
 
 use crate::condition::{ContinuousConditionType, DiscreteConditionType, Response, TimeOut};
 use crate::timed_spline::{InterpolatorError, TimedSpline};
 use crate::Condition;
 use crate::MotionFile;
+use color_eyre::eyre::{bail, eyre};
 use color_eyre::{Report, Result};
 use itertools::Itertools;
 use serde::{Deserialize, Serialize};
 use splines::Interpolate;
 use types::ConditionInput;
 
+/// Named `MotionFile`s available to be spliced into a motion via a
+/// `sub_motion` frame reference, keyed by the name the referencing frame
+/// uses.
+pub type MotionLibrary<T> = HashMap<String, MotionFile<T>>;
+
+/// Types that can report where the corresponding joints actually are right
+/// now, so the closed-loop feedback controller has something to compare the
+/// commanded spline position against.
+pub trait MeasuredPosition {
+    fn measured_position(condition_input: &ConditionInput) -> Self;
+}
+
+/// Types whose components can be clamped to a minimum/maximum, used to keep
+/// PID commands within a joint's physical range of motion.
+pub trait ClampToLimits {
+    fn clamp_to_limits(self, minimum: Self, maximum: Self) -> Self;
+}
+
+/// Gains for the optional closed-loop PID correction applied on top of the
+/// feed-forward spline position. `tracking` is the back-calculation gain used
+/// to unwind the integrator once the commanded position saturates against
+/// `minimum`/`maximum`. Leaving this out of a `MotionFile` keeps the frame
+/// purely feed-forward, exactly as before.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct PidGains<T> {
+    pub proportional: T,
+    pub integral: T,
+    pub derivative: T,
+    pub tracking: T,
+    pub minimum: T,
+    pub maximum: T,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct PidTrackingState<T> {
+    integral: T,
+    previous_error: T,
+}
+
+/// Pure PID + back-calculation anti-windup step: given the current target and
+/// measured position, the configured gains, the previous cycle's tracking
+/// state and the elapsed time, returns the clamped command to send this cycle
+/// and the tracking state to carry into the next one. Kept free of `self` so
+/// the anti-windup arithmetic can be exercised directly in tests without a
+/// full `MotionInterpolator`.
+fn compute_pid_command<T>(
+    target: T,
+    measured: T,
+    gains: PidGains<T>,
+    previous_state: PidTrackingState<T>,
+    dt: f32,
+) -> (T, PidTrackingState<T>)
+where
+    T: Copy
+        + Add<Output = T>
+        + Sub<Output = T>
+        + Mul<Output = T>
+        + Mul<f32, Output = T>
+        + ClampToLimits,
+{
+    let error = target - measured;
+    let derivative = (error - previous_state.previous_error) * (1.0 / dt);
+    let unclamped_command = target
+        + gains.proportional * error
+        + gains.integral * previous_state.integral
+        + gains.derivative * derivative;
+    let clamped_command = unclamped_command.clamp_to_limits(gains.minimum, gains.maximum);
+    let saturation_error = clamped_command - unclamped_command;
+
+    let next_state = PidTrackingState {
+        integral: previous_state.integral + error * dt + saturation_error * gains.tracking,
+        previous_error: error,
+    };
+
+    (clamped_command, next_state)
+}
+
+/// How many recent evaluations of a condition are kept for the majority
+/// (debounced) vote, when a `ConditionedSpline` does not override it.
+const DEFAULT_DEBOUNCE_WINDOW: usize = 5;
+
+/// Ring buffer of the most recent `Response`s observed for a single
+/// condition, used to reject single-frame sensor glitches: a transition only
+/// fires once a majority of the window agrees, instead of on the first
+/// matching frame. Until the window has filled (e.g. right after a frame
+/// starts) it reports `Continue` whatever its votes are, so a
+/// partially-filled window can never abort a motion.
+#[derive(Debug, Clone)]
+struct ResponseWindow {
+    responses: VecDeque<Response>,
+    capacity: usize,
+}
+
+impl ResponseWindow {
+    fn new(capacity: usize) -> Self {
+        Self {
+            responses: VecDeque::with_capacity(capacity),
+            capacity: capacity.max(1),
+        }
+    }
+
+    fn push(&mut self, response: Response) {
+        if self.responses.len() == self.capacity {
+            self.responses.pop_front();
+        }
+        self.responses.push_back(response);
+    }
+
+    fn majority(&self) -> Response {
+        // A not-yet-full window can never outvote its missing slots: those
+        // are implicitly Continue, so a few real votes alone could already
+        // clear half of `capacity` before the window has actually filled,
+        // firing on the first few frames exactly like the un-debounced
+        // behavior this window exists to replace.
+        if self.responses.len() != self.capacity {
+            return Response::Continue;
+        }
+
+        let abort_votes = self
+            .responses
+            .iter()
+            .filter(|response| matches!(response, Response::Abort))
+            .count();
+        let wait_votes = self
+            .responses
+            .iter()
+            .filter(|response| matches!(response, Response::Wait))
+            .count();
+
+        // Precedence stays Abort > Wait > Continue, each requiring a
+        // majority of the full window.
+        if abort_votes * 2 > self.capacity {
+            Response::Abort
+        } else if wait_votes * 2 > self.capacity {
+            Response::Wait
+        } else {
+            Response::Continue
+        }
+    }
+}
+
+impl Default for ResponseWindow {
+    fn default() -> Self {
+        Self::new(DEFAULT_DEBOUNCE_WINDOW)
+    }
+}
+
 #[derive(Debug, Default, Serialize, Deserialize)]
 pub struct ConditionedSpline<T> {
     pub entry_condition: Option<DiscreteConditionType>,
     pub motion_interrupts: Option<Vec<ContinuousConditionType>>,
     pub spline: TimedSpline<T>,
     pub exit_condition: Option<DiscreteConditionType>,
+    #[serde(default)]
+    pub pid_gains: Option<PidGains<T>>,
+    /// Number of recent evaluations of each of this frame's conditions over
+    /// which a majority vote decides whether a transition fires. Defaults to
+    /// `DEFAULT_DEBOUNCE_WINDOW` when unset.
+    #[serde(default)]
+    pub debounce_window: Option<usize>,
+    /// A nested interpolator for the `MotionFile` this frame references by
+    /// name, resolved (and cycle-checked) once at construction time. While
+    /// this is `Some`, the nested interpolator supplies the commanded
+    /// position from the cycle after the frame starts interpolating until
+    /// the sub-motion finishes. The frame's own `spline` seeds the sub-motion
+    /// with its start position, and its start and end positions are still
+    /// what `value()` reports during the frame's entry and exit checks.
+    #[serde(skip)]
+    pub sub_motion: Option<Box<MotionInterpolator<T>>>,
 }
 
 #[derive(Default, Debug)]
 pub struct MotionInterpolator<T> {
     frames: Vec<ConditionedSpline<T>>,
     current_state: State<T>,
+    pid_state: PidTrackingState<T>,
+    last_command: Option<T>,
+    entry_window: ResponseWindow,
+    exit_window: ResponseWindow,
+    continuous_windows: Vec<ResponseWindow>,
+    /// Frame index `continuous_windows` was last (re)built for, so a frame
+    /// change is detected even when the new frame's `motion_interrupts` has
+    /// the same length as the previous frame's.
+    continuous_frame_index: Option<usize>,
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -79,35 +254,66 @@ impl<T> Default for State<T> {
     }
 }
 
-impl<T: Debug + Interpolate<f32>> MotionInterpolator<T> {
+impl<T> MotionInterpolator<T>
+where
+    T: Debug
+        + Interpolate<f32>
+        + Copy
+        + Default
+        + Add<Output = T>
+        + Sub<Output = T>
+        + Mul<Output = T>
+        + Mul<f32, Output = T>
+        + ClampToLimits
+        + MeasuredPosition,
+{
     fn check_continuous_conditions(&mut self, condition_input: &ConditionInput) -> ReturnState {
-        if let Some(continuous_conditions) = self
-            .current_state
-            .current_frame_index()
-            .and_then(|frame_index| self.frames[frame_index].motion_interrupts.as_ref())
-        {
-            return match continuous_conditions
+        let Some(current_frame_index) = self.current_state.current_frame_index() else {
+            return ReturnState::Continue;
+        };
+        let Some(continuous_conditions) = self.frames[current_frame_index]
+            .motion_interrupts
+            .as_ref()
+        else {
+            return ReturnState::Continue;
+        };
+
+        if self.continuous_frame_index != Some(current_frame_index) {
+            let window_size = self.frames[current_frame_index]
+                .debounce_window
+                .unwrap_or(DEFAULT_DEBOUNCE_WINDOW);
+            self.continuous_windows = continuous_conditions
                 .iter()
-                .map(|condition| condition.evaluate(condition_input))
-                .reduce(|accumulated, current| match (&accumulated, &current) {
-                    (Response::Abort, _) => Response::Abort,
-                    (_, Response::Abort) => Response::Abort,
-                    (Response::Wait, _) => Response::Wait,
-                    (_, Response::Wait) => Response::Wait,
-                    _ => accumulated,
-                }) {
-                Some(Response::Abort) => {
-                    self.current_state = State::Aborted {
-                        at_position: self.value(),
-                    };
-                    ReturnState::Return
-                }
-                Some(Response::Wait) => ReturnState::Return,
-                _ => ReturnState::Continue,
-            };
+                .map(|_| ResponseWindow::new(window_size))
+                .collect();
+            self.continuous_frame_index = Some(current_frame_index);
         }
 
-        ReturnState::Continue
+        let debounced = continuous_conditions
+            .iter()
+            .zip(self.continuous_windows.iter_mut())
+            .map(|(condition, window)| {
+                window.push(condition.evaluate(condition_input));
+                window.majority()
+            })
+            .reduce(|accumulated, current| match (&accumulated, &current) {
+                (Response::Abort, _) => Response::Abort,
+                (_, Response::Abort) => Response::Abort,
+                (Response::Wait, _) => Response::Wait,
+                (_, Response::Wait) => Response::Wait,
+                _ => accumulated,
+            });
+
+        match debounced {
+            Some(Response::Abort) => {
+                self.current_state = State::Aborted {
+                    at_position: self.value(),
+                };
+                ReturnState::Return
+            }
+            Some(Response::Wait) => ReturnState::Return,
+            _ => ReturnState::Continue,
+        }
     }
 
     fn advance_state(&mut self, time_step: Duration, condition_input: &ConditionInput) {
@@ -116,20 +322,44 @@ impl<T: Debug + Interpolate<f32>> MotionInterpolator<T> {
                 current_frame_index,
                 time_since_start,
             } => {
-                let current_frame = &self.frames[current_frame_index];
-                match current_frame.entry_condition.as_ref().map(|condition| {
-                    condition
-                        .evaluate(condition_input)
-                        .with_timeout(condition.timeout(time_since_start))
-                }) {
-                    Some(Response::Abort) => State::Aborted {
-                        at_position: self.value(),
-                    },
-                    Some(Response::Wait) => State::CheckEntry {
-                        current_frame_index,
-                        time_since_start: time_since_start + time_step,
-                    },
-                    _ => State::InterpolateSpline {
+                let raw_response = {
+                    let current_frame = &self.frames[current_frame_index];
+                    current_frame.entry_condition.as_ref().map(|condition| {
+                        condition
+                            .evaluate(condition_input)
+                            .with_timeout(condition.timeout(time_since_start))
+                    })
+                };
+
+                match raw_response {
+                    Some(response) => {
+                        self.entry_window.push(response);
+                        match self.entry_window.majority() {
+                            Response::Abort => State::Aborted {
+                                at_position: self.value(),
+                            },
+                            Response::Wait => State::CheckEntry {
+                                current_frame_index,
+                                time_since_start: time_since_start + time_step,
+                            },
+                            // Hold until the window has filled, so entering
+                            // the frame also needs a full-window majority.
+                            Response::Continue
+                                if self.entry_window.responses.len()
+                                    < self.entry_window.capacity =>
+                            {
+                                State::CheckEntry {
+                                    current_frame_index,
+                                    time_since_start: time_since_start + time_step,
+                                }
+                            }
+                            Response::Continue => State::InterpolateSpline {
+                                current_frame_index,
+                                time_since_start: Duration::ZERO,
+                            },
+                        }
+                    }
+                    None => State::InterpolateSpline {
                         current_frame_index,
                         time_since_start: Duration::ZERO,
                     },
@@ -156,12 +386,21 @@ impl<T: Debug + Interpolate<f32>> MotionInterpolator<T> {
                 current_frame_index,
                 time_since_start,
             } => {
-                let current_frame = &self.frames[current_frame_index];
-                match current_frame.exit_condition.as_ref().map(|condition| {
-                    condition
-                        .evaluate(condition_input)
-                        .with_timeout(condition.timeout(time_since_start))
-                }) {
+                let raw_response = {
+                    let current_frame = &self.frames[current_frame_index];
+                    current_frame.exit_condition.as_ref().map(|condition| {
+                        condition
+                            .evaluate(condition_input)
+                            .with_timeout(condition.timeout(time_since_start))
+                    })
+                };
+
+                let debounced_response = raw_response.map(|response| {
+                    self.exit_window.push(response);
+                    self.exit_window.majority()
+                });
+
+                match debounced_response {
                     Some(Response::Abort) => State::Aborted {
                         at_position: self.value(),
                     },
@@ -169,6 +408,14 @@ impl<T: Debug + Interpolate<f32>> MotionInterpolator<T> {
                         current_frame_index,
                         time_since_start: time_since_start + time_step,
                     },
+                    Some(Response::Continue)
+                        if self.exit_window.responses.len() < self.exit_window.capacity =>
+                    {
+                        State::CheckExit {
+                            current_frame_index,
+                            time_since_start: time_since_start + time_step,
+                        }
+                    }
                     _ if current_frame_index < self.frames.len() - 1 => State::CheckEntry {
                         current_frame_index: current_frame_index + 1,
                         time_since_start: Duration::ZERO,
@@ -180,12 +427,146 @@ impl<T: Debug + Interpolate<f32>> MotionInterpolator<T> {
         };
     }
 
+    /// Recomputes the closed-loop PID command for the current frame, if it
+    /// configures gains, using the joint positions actually measured this
+    /// cycle. Anti-windup is implemented via back-calculation: once the
+    /// command is clamped to the joint limits, the difference between the
+    /// clamped and unclamped command is fed back into the integral through
+    /// `tracking`, so the integrator unwinds instead of continuing to wind up
+    /// while saturated. Frames without gains fall back to pure feed-forward.
+    fn update_feedback_command(&mut self, time_step: Duration, condition_input: &ConditionInput) {
+        let State::InterpolateSpline {
+            current_frame_index,
+            time_since_start,
+        } = self.current_state
+        else {
+            self.last_command = None;
+            return;
+        };
+
+        let current_frame = &self.frames[current_frame_index];
+        let Some(gains) = current_frame.pid_gains else {
+            self.last_command = None;
+            return;
+        };
+
+        let target = current_frame.spline.value_at(time_since_start);
+        let measured = T::measured_position(condition_input);
+        let dt = time_step.as_secs_f32().max(f32::EPSILON);
+
+        let (command, next_pid_state) =
+            compute_pid_command(target, measured, gains, self.pid_state, dt);
+        self.pid_state = next_pid_state;
+        self.last_command = Some(command);
+    }
+
+    /// Resets the debounce windows (and PID state) whenever a frame is
+    /// freshly entered, so a window never carries votes over from a
+    /// different condition or a previous run through this frame.
+    fn reset_windows_on_frame_start(&mut self) {
+        match self.current_state {
+            State::CheckEntry {
+                current_frame_index,
+                time_since_start,
+            } if time_since_start.is_zero() => {
+                let window_size = self.frames[current_frame_index]
+                    .debounce_window
+                    .unwrap_or(DEFAULT_DEBOUNCE_WINDOW);
+                self.entry_window = ResponseWindow::new(window_size);
+            }
+            State::InterpolateSpline {
+                current_frame_index,
+                time_since_start,
+            } if time_since_start.is_zero() => {
+                self.continuous_windows.clear();
+                self.continuous_frame_index = None;
+                self.pid_state = PidTrackingState::default();
+
+                let start_position = self.frames[current_frame_index].spline.start_position();
+                if let Some(sub_motion) = self.frames[current_frame_index].sub_motion.as_mut() {
+                    sub_motion.set_initial_positions(start_position);
+                }
+            }
+            State::CheckExit {
+                current_frame_index,
+                time_since_start,
+            } if time_since_start.is_zero() => {
+                let window_size = self.frames[current_frame_index]
+                    .debounce_window
+                    .unwrap_or(DEFAULT_DEBOUNCE_WINDOW);
+                self.exit_window = ResponseWindow::new(window_size);
+            }
+            _ => {}
+        }
+    }
+
+    /// If the current frame invokes a sub-motion, advances the nested
+    /// interpolator instead of this frame's own spline, seeding `last_command`
+    /// from it. Once the child finishes, pops it (by resetting it for its
+    /// next activation) and continues this interpolator at `CheckExit`, or
+    /// straight to `Aborted` if the child itself aborted. Returns `true` while
+    /// a sub-motion owns the commanded position this cycle, telling the
+    /// caller to skip its own state transition.
+    fn advance_sub_motion(&mut self, time_step: Duration, condition_input: &ConditionInput) -> bool {
+        let State::InterpolateSpline {
+            current_frame_index,
+            ..
+        } = self.current_state
+        else {
+            return false;
+        };
+
+        let Some(child) = self.frames[current_frame_index].sub_motion.as_mut() else {
+            return false;
+        };
+
+        child.advance_by(time_step, condition_input);
+        let child_position = child.value();
+
+        if child.is_finished() {
+            let child_aborted = child.is_aborted();
+            child.reset();
+            self.current_state = if child_aborted {
+                State::Aborted {
+                    at_position: child_position,
+                }
+            } else {
+                // `reset_windows_on_frame_start` never runs for this
+                // transition (advance_by returns early for sub-motion
+                // frames), so reset the exit window here the same way it
+                // does, or the next CheckExit evaluation would mix fresh
+                // votes with whatever this window last held.
+                let window_size = self.frames[current_frame_index]
+                    .debounce_window
+                    .unwrap_or(DEFAULT_DEBOUNCE_WINDOW);
+                self.exit_window = ResponseWindow::new(window_size);
+                State::CheckExit {
+                    current_frame_index,
+                    time_since_start: Duration::ZERO,
+                }
+            };
+        }
+
+        self.last_command = Some(child_position);
+        true
+    }
+
+    fn is_aborted(&self) -> bool {
+        matches!(self.current_state, State::Aborted { .. })
+    }
+
     pub fn advance_by(&mut self, time_step: Duration, condition_input: &ConditionInput) {
         if let ReturnState::Return = self.check_continuous_conditions(condition_input) {
             return;
         }
 
+        if self.advance_sub_motion(time_step, condition_input) {
+            return;
+        }
+
         self.advance_state(time_step, condition_input);
+        self.reset_windows_on_frame_start();
+        self.update_feedback_command(time_step, condition_input);
     }
 
     pub fn is_finished(&self) -> bool {
@@ -201,9 +582,11 @@ impl<T: Debug + Interpolate<f32>> MotionInterpolator<T> {
             State::InterpolateSpline {
                 current_frame_index,
                 time_since_start,
-            } => self.frames[current_frame_index]
-                .spline
-                .value_at(time_since_start),
+            } => self.last_command.unwrap_or_else(|| {
+                self.frames[current_frame_index]
+                    .spline
+                    .value_at(time_since_start)
+            }),
             State::CheckExit {
                 current_frame_index,
                 ..
@@ -218,6 +601,23 @@ impl<T: Debug + Interpolate<f32>> MotionInterpolator<T> {
             current_frame_index: 0,
             time_since_start: Duration::ZERO,
         };
+        self.pid_state = PidTrackingState::default();
+        self.last_command = None;
+        let first_window_size = self
+            .frames
+            .first()
+            .and_then(|frame| frame.debounce_window)
+            .unwrap_or(DEFAULT_DEBOUNCE_WINDOW);
+        self.entry_window = ResponseWindow::new(first_window_size);
+        self.exit_window = ResponseWindow::default();
+        self.continuous_windows.clear();
+        self.continuous_frame_index = None;
+
+        for frame in &mut self.frames {
+            if let Some(sub_motion) = frame.sub_motion.as_mut() {
+                sub_motion.reset();
+            }
+        }
     }
 
     pub fn set_initial_positions(&mut self, position: T) {
@@ -227,10 +627,27 @@ impl<T: Debug + Interpolate<f32>> MotionInterpolator<T> {
     }
 }
 
-impl<T: Debug + Interpolate<f32>> TryFrom<MotionFile<T>> for MotionInterpolator<T> {
+impl<T: Debug + Interpolate<f32> + Default + Clone> TryFrom<MotionFile<T>> for MotionInterpolator<T> {
     type Error = Report;
 
     fn try_from(motion_file: MotionFile<T>) -> Result<Self> {
+        Self::try_from_with_library(motion_file, &MotionLibrary::new(), &mut HashSet::new())
+    }
+}
+
+impl<T: Debug + Interpolate<f32> + Default + Clone> MotionInterpolator<T> {
+    /// Builds an interpolator the same way `TryFrom<MotionFile<T>>` does, but
+    /// additionally resolves any `sub_motion` frame references against
+    /// `library` by name, recursively building their interpolators too.
+    /// `visiting` tracks the chain of motion names currently being resolved,
+    /// so that a sub-motion which (directly or transitively) references
+    /// itself is rejected here at construction time instead of recursing
+    /// forever the first time the motion is played.
+    pub fn try_from_with_library(
+        motion_file: MotionFile<T>,
+        library: &MotionLibrary<T>,
+        visiting: &mut HashSet<String>,
+    ) -> Result<Self> {
         let interpolation_mode = motion_file.interpolation_mode;
 
         let first_frame = motion_file.motion.first().unwrap();
@@ -244,27 +661,32 @@ impl<T: Debug + Interpolate<f32>> TryFrom<MotionFile<T>> for MotionInterpolator<
                 interpolation_mode,
             )?,
             exit_condition: first_frame.exit_condition.clone(),
+            pid_gains: first_frame.pid_gains.clone(),
+            debounce_window: first_frame.debounce_window,
+            sub_motion: resolve_sub_motion(first_frame.sub_motion.as_deref(), library, visiting)?,
         }];
 
-        motion_frames.extend(
-            motion_file
-                .motion
-                .into_iter()
-                .tuple_windows()
-                .map(|(first_frame, second_frame)| {
-                    Ok(ConditionedSpline {
-                        entry_condition: second_frame.entry_condition,
-                        motion_interrupts: second_frame.motion_interrupts,
-                        spline: TimedSpline::try_new_with_start(
-                            first_frame.keyframes.last().unwrap().positions,
-                            second_frame.keyframes,
-                            interpolation_mode,
-                        )?,
-                        exit_condition: second_frame.exit_condition,
-                    })
-                })
-                .collect::<Result<Vec<_>, InterpolatorError>>()?,
-        );
+        for (first_frame, second_frame) in motion_file.motion.into_iter().tuple_windows() {
+            let sub_motion =
+                resolve_sub_motion(second_frame.sub_motion.as_deref(), library, visiting)?;
+            motion_frames.push(ConditionedSpline {
+                entry_condition: second_frame.entry_condition,
+                motion_interrupts: second_frame.motion_interrupts,
+                spline: TimedSpline::try_new_with_start(
+                    first_frame.keyframes.last().unwrap().positions,
+                    second_frame.keyframes,
+                    interpolation_mode,
+                )?,
+                exit_condition: second_frame.exit_condition,
+                pid_gains: second_frame.pid_gains,
+                debounce_window: second_frame.debounce_window,
+                sub_motion,
+            });
+        }
+
+        let first_window_size = motion_frames[0]
+            .debounce_window
+            .unwrap_or(DEFAULT_DEBOUNCE_WINDOW);
 
         Ok(Self {
             current_state: State::CheckEntry {
@@ -272,6 +694,172 @@ impl<T: Debug + Interpolate<f32>> TryFrom<MotionFile<T>> for MotionInterpolator<
                 time_since_start: Duration::ZERO,
             },
             frames: motion_frames,
+            pid_state: PidTrackingState::default(),
+            last_command: None,
+            entry_window: ResponseWindow::new(first_window_size),
+            exit_window: ResponseWindow::default(),
+            continuous_windows: Vec::new(),
+            continuous_frame_index: None,
         })
     }
 }
+
+/// Resolves a frame's `sub_motion` reference (if any) into a freshly-built,
+/// cycle-checked nested interpolator. See
+/// [`MotionInterpolator::try_from_with_library`] for the meaning of
+/// `visiting`.
+fn resolve_sub_motion<T: Debug + Interpolate<f32> + Default + Clone>(
+    name: Option<&str>,
+    library: &MotionLibrary<T>,
+    visiting: &mut HashSet<String>,
+) -> Result<Option<Box<MotionInterpolator<T>>>> {
+    let Some(name) = name else {
+        return Ok(None);
+    };
+
+    if !visiting.insert(name.to_owned()) {
+        bail!(
+            "sub-motion composition cycle detected: \"{name}\" references itself, \
+             directly or transitively"
+        );
+    }
+
+    let motion_file = library
+        .get(name)
+        .ok_or_else(|| eyre!("unknown sub-motion \"{name}\""))?
+        .clone();
+    let child = MotionInterpolator::try_from_with_library(motion_file, library, visiting)?;
+
+    visiting.remove(name);
+
+    Ok(Some(Box::new(child)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    impl ClampToLimits for f32 {
+        fn clamp_to_limits(self, minimum: Self, maximum: Self) -> Self {
+            self.clamp(minimum, maximum)
+        }
+    }
+
+    #[test]
+    fn majority_requires_more_than_half_the_window_to_flip() {
+        let mut window = ResponseWindow::new(4);
+        window.push(Response::Abort);
+        window.push(Response::Continue);
+        assert!(matches!(window.majority(), Response::Continue));
+
+        window.push(Response::Abort);
+        window.push(Response::Abort);
+        assert!(matches!(window.majority(), Response::Abort));
+    }
+
+    #[test]
+    fn majority_never_aborts_on_a_partially_filled_window() {
+        let mut window = ResponseWindow::new(5);
+        window.push(Response::Abort);
+        window.push(Response::Abort);
+        window.push(Response::Abort);
+        assert!(matches!(window.majority(), Response::Continue));
+
+        window.push(Response::Abort);
+        window.push(Response::Abort);
+        assert!(matches!(window.majority(), Response::Abort));
+    }
+
+    #[test]
+    fn majority_ignores_a_single_glitching_frame() {
+        let mut window = ResponseWindow::new(5);
+        for _ in 0..4 {
+            window.push(Response::Continue);
+        }
+        window.push(Response::Abort);
+        assert!(matches!(window.majority(), Response::Continue));
+    }
+
+    #[test]
+    fn majority_drops_votes_once_the_window_is_full() {
+        let mut window = ResponseWindow::new(2);
+        window.push(Response::Abort);
+        window.push(Response::Abort);
+        assert!(matches!(window.majority(), Response::Abort));
+
+        window.push(Response::Continue);
+        window.push(Response::Continue);
+        assert!(matches!(window.majority(), Response::Continue));
+    }
+
+    #[test]
+    fn pid_command_clamps_and_back_calculates_the_integral() {
+        let gains = PidGains {
+            proportional: 1.0,
+            integral: 1.0,
+            derivative: 0.0,
+            tracking: 1.0,
+            minimum: -1.0,
+            maximum: 1.0,
+        };
+
+        // error = 10.0 - 0.0 = 10.0, so the unclamped command saturates hard
+        // against `maximum`; the integral should be pulled back via
+        // `tracking` instead of winding up by the full `error * dt`.
+        let (command, next_state) =
+            compute_pid_command(10.0, 0.0, gains, PidTrackingState::default(), 0.1);
+
+        assert_eq!(command, 1.0);
+        assert!(next_state.integral < 10.0 * 0.1);
+    }
+
+    #[test]
+    fn pid_command_is_pure_feed_forward_when_error_is_zero() {
+        let gains = PidGains {
+            proportional: 1.0,
+            integral: 1.0,
+            derivative: 1.0,
+            tracking: 1.0,
+            minimum: -10.0,
+            maximum: 10.0,
+        };
+
+        let (command, next_state) =
+            compute_pid_command(2.0, 2.0, gains, PidTrackingState::default(), 0.1);
+
+        assert_eq!(command, 2.0);
+        assert_eq!(next_state.integral, 0.0);
+        assert_eq!(next_state.previous_error, 0.0);
+    }
+
+    #[test]
+    fn resolve_sub_motion_is_a_noop_without_a_reference() {
+        let library: MotionLibrary<f32> = MotionLibrary::new();
+        let mut visiting = HashSet::new();
+
+        let resolved = resolve_sub_motion::<f32>(None, &library, &mut visiting).unwrap();
+
+        assert!(resolved.is_none());
+    }
+
+    #[test]
+    fn resolve_sub_motion_reports_unknown_names() {
+        let library: MotionLibrary<f32> = MotionLibrary::new();
+        let mut visiting = HashSet::new();
+
+        let result = resolve_sub_motion::<f32>(Some("missing"), &library, &mut visiting);
+
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn resolve_sub_motion_detects_a_cycle_before_touching_the_library() {
+        let library: MotionLibrary<f32> = MotionLibrary::new();
+        let mut visiting = HashSet::new();
+        visiting.insert("wave".to_owned());
+
+        let result = resolve_sub_motion::<f32>(Some("wave"), &library, &mut visiting);
+
+        assert!(result.is_err());
+    }
+}
@@ -0,0 +1,134 @@
+use std::fmt::Debug;
+use std::ops::{Add, Mul, Sub};
+use std::time::Duration;
+
+use color_eyre::Result;
+use splines::Interpolate;
+use types::ConditionInput;
+
+use crate::motion_interpolator::{ClampToLimits, MeasuredPosition, MotionInterpolator};
+use crate::MotionFile;
+
+/// Drives a single commanded position out of up to three `MotionInterpolator`s
+/// without ever producing a jump at a handoff:
+///
+/// - `startup`, if configured, plays once, starting as soon as the scheduler
+///   is constructed.
+/// - a `requested` motion, set explicitly by the node, which takes priority
+///   over everything else while it is running, and discards `startup` (it
+///   would otherwise be resumed from its stale, un-advanced position once
+///   `requested` finishes).
+/// - `idle`, if configured, which loops (via `reset()`) whenever nothing
+///   higher priority is active and the previous motion has finished.
+///
+/// Whichever interpolator finishes seeds the next one's initial position with
+/// its own last commanded value via `set_initial_positions`, so there is no
+/// discontinuity at the switch.
+pub struct MotionScheduler<T> {
+    startup: Option<MotionInterpolator<T>>,
+    idle: Option<MotionInterpolator<T>>,
+    requested: Option<MotionInterpolator<T>>,
+}
+
+impl<T> MotionScheduler<T>
+where
+    T: Debug
+        + Interpolate<f32>
+        + Copy
+        + Default
+        + Add<Output = T>
+        + Sub<Output = T>
+        + Mul<Output = T>
+        + Mul<f32, Output = T>
+        + ClampToLimits
+        + MeasuredPosition,
+{
+    pub fn new(startup: Option<MotionFile<T>>, idle: Option<MotionFile<T>>) -> Result<Self> {
+        Ok(Self {
+            startup: startup.map(MotionInterpolator::try_from).transpose()?,
+            idle: idle.map(MotionInterpolator::try_from).transpose()?,
+            requested: None,
+        })
+    }
+
+    /// Advances whichever motion has priority this cycle and returns the
+    /// position that should be commanded, or `None` if nothing is configured
+    /// and there is nothing to command.
+    pub fn advance_by(
+        &mut self,
+        time_step: Duration,
+        condition_input: &ConditionInput,
+    ) -> Option<T> {
+        if let Some(requested) = &mut self.requested {
+            requested.advance_by(time_step, condition_input);
+            let position = requested.value();
+            if requested.is_finished() {
+                self.requested = None;
+                self.hand_off_to_idle(position);
+            }
+            // `requested` takes priority over `startup`/`idle` for the whole
+            // cycle it ran in, finished or not, so the position it commanded
+            // is always what gets returned here — falling through to
+            // `startup` below would otherwise resume it from wherever it was
+            // frozen while `requested` was running, producing exactly the
+            // jump this scheduler exists to avoid.
+            return Some(position);
+        }
+
+        if let Some(startup) = &mut self.startup {
+            startup.advance_by(time_step, condition_input);
+            let position = startup.value();
+            if startup.is_finished() {
+                self.startup = None;
+                self.hand_off_to_idle(position);
+            } else {
+                return Some(position);
+            }
+        }
+
+        if let Some(idle) = &mut self.idle {
+            idle.advance_by(time_step, condition_input);
+            if idle.is_finished() {
+                let last_position = idle.value();
+                idle.reset();
+                idle.set_initial_positions(last_position);
+            }
+            return Some(idle.value());
+        }
+
+        None
+    }
+
+    /// Requests a new motion with priority over `startup` and `idle`, seeding
+    /// it with whatever position is currently being commanded so the handoff
+    /// has no jump. Discards `startup` outright rather than leaving it to be
+    /// resumed later from a stale position once `requested` finishes.
+    pub fn request(&mut self, mut motion: MotionInterpolator<T>) {
+        if let Some(current_position) = self.current_position() {
+            motion.set_initial_positions(current_position);
+        }
+        self.startup = None;
+        self.requested = Some(motion);
+    }
+
+    fn hand_off_to_idle(&mut self, last_position: T) {
+        if let Some(idle) = &mut self.idle {
+            // `idle` loops continuously whenever nothing else is active, so
+            // it is almost never sitting at its pristine frame-0 state when
+            // this is called. Reset it back to frame 0 before seeding the
+            // position, or `set_initial_positions` would rewrite a spline
+            // `advance_by` has already moved past, and idle would resume
+            // from wherever it was frozen instead of the handoff position.
+            idle.reset();
+            idle.set_initial_positions(last_position);
+        }
+    }
+
+    fn current_position(&self) -> Option<T> {
+        self.requested
+            .as_ref()
+            .map(MotionInterpolator::value)
+            .or_else(|| self.startup.as_ref().map(MotionInterpolator::value))
+            .or_else(|| self.idle.as_ref().map(MotionInterpolator::value))
+    }
+}
@@ -1,4 +1,7 @@
+use std::fs;
+use std::path::PathBuf;
 use std::str::FromStr;
+use std::time::Duration;
 
 use clap::Parser;
 use color_eyre::{eyre::bail, Result};
@@ -6,17 +9,34 @@ use communication::{
     client::{Communication, CyclerOutput, SubscriberMessage},
     messages::Format,
 };
-use log::{error, info};
+use log::{error, info, warn};
+use rand::Rng;
+use types::{ImageLines, LineData};
 
 use crate::logging::setup_logger;
 
 mod logging;
 
+const RECONNECT_BASE_BACKOFF: Duration = Duration::from_millis(500);
+const RECONNECT_MAX_BACKOFF: Duration = Duration::from_secs(30);
+
 #[derive(Parser, Debug)]
 #[clap(author, version, about, long_about = None)]
 struct CommandlineArguments {
     #[clap(short, long, default_value = "localhost")]
     address: String,
+    /// Deserialize binary payloads (e.g. LineData, ImageLines) and print a
+    /// structured summary instead of bailing out
+    #[clap(long)]
+    decode: bool,
+    /// Write raw binary frames into this directory, one file per frame with
+    /// an incrementing index
+    #[clap(long)]
+    output: Option<PathBuf>,
+    /// Give up after this many consecutive failed reconnection attempts
+    /// instead of retrying forever
+    #[clap(long)]
+    max_reconnect_attempts: Option<u32>,
     path: String,
 }
 
@@ -44,20 +64,106 @@ async fn main() -> Result<()> {
 
     let arguments = CommandlineArguments::parse();
     let output_to_subscribe = CyclerOutput::from_str(&arguments.path)?;
-    let communication = Communication::new(Some(format!("ws://{}:1337", arguments.address)), true);
-    let (_uuid, mut receiver) = communication
-        .subscribe_output(output_to_subscribe, Format::Textual)
-        .await;
-    while let Some(message) = receiver.recv().await {
-        match message {
-            SubscriberMessage::Update { value } => println!("{value:#}"),
-            SubscriberMessage::SubscriptionSuccess => info!("Successfully subscribed"),
-            SubscriberMessage::SubscriptionFailure { info } => {
-                error!("Failed to subscribe: {info:?}");
-                break;
+
+    if let Some(output_directory) = &arguments.output {
+        fs::create_dir_all(output_directory)?;
+    }
+
+    let mut frame_index = 0;
+    let mut reconnect_attempt = 0;
+
+    loop {
+        let communication =
+            Communication::new(Some(format!("ws://{}:1337", arguments.address)), true);
+        let (_uuid, mut receiver) = communication
+            .subscribe_output(output_to_subscribe.clone(), Format::Textual)
+            .await;
+
+        while let Some(message) = receiver.recv().await {
+            reconnect_attempt = 0;
+            match message {
+                SubscriberMessage::Update { value } => println!("{value:#}"),
+                SubscriberMessage::SubscriptionSuccess => info!("Successfully subscribed"),
+                SubscriberMessage::SubscriptionFailure { info } => {
+                    error!("Failed to subscribe: {info:?}");
+                    return Ok(());
+                }
+                SubscriberMessage::UpdateBinary { data } => {
+                    if let Some(output_directory) = &arguments.output {
+                        let frame_path = output_directory.join(format!("{frame_index:06}.bin"));
+                        fs::write(&frame_path, &data)?;
+                        info!("Wrote frame to {}", frame_path.display());
+                        frame_index += 1;
+                    }
+
+                    if arguments.decode {
+                        match decode_binary_payload(&arguments.path, &data) {
+                            Ok(summary) => println!("{summary}"),
+                            Err(report) => error!("{report}"),
+                        }
+                    } else if arguments.output.is_none() {
+                        bail!("Cannot print binary data, pass --decode or --output to consume it");
+                    }
+                }
+            }
+        }
+
+        reconnect_attempt += 1;
+        if let Some(max_attempts) = arguments.max_reconnect_attempts {
+            if reconnect_attempt > max_attempts {
+                bail!("Giving up after {max_attempts} failed reconnection attempts to {}", arguments.address);
             }
-            SubscriberMessage::UpdateBinary { .. } => bail!("Cannot print binary data"),
         }
+
+        let backoff = reconnect_backoff_with_jitter(reconnect_attempt);
+        warn!(
+            "Lost connection to {}, reconnecting in {:.1}s (attempt {reconnect_attempt})",
+            arguments.address,
+            backoff.as_secs_f32(),
+        );
+        tokio::time::sleep(backoff).await;
+    }
+}
+
+/// Exponential backoff (base 500 ms, factor 2, capped at 30 s) with ±50%
+/// jitter, so that many clients losing the connection at once (e.g. a NAO
+/// reboot) don't all retry in lockstep.
+fn reconnect_backoff_with_jitter(attempt: u32) -> Duration {
+    let exponential = RECONNECT_BASE_BACKOFF
+        .saturating_mul(2u32.saturating_pow(attempt.saturating_sub(1)))
+        .min(RECONNECT_MAX_BACKOFF);
+    let jitter_factor = rand::thread_rng().gen_range(0.5..=1.5);
+    // Jitter can scale the already-capped exponential term back up past
+    // `RECONNECT_MAX_BACKOFF` (up to 1.5x), so the cap has to be re-applied
+    // after jitter, not just before it, for it to actually bound the sleep.
+    exponential.mul_f32(jitter_factor).min(RECONNECT_MAX_BACKOFF)
+}
+
+/// Decodes a binary payload into the type its subscribed output path
+/// declares, rendering a structured summary (shape, counts) instead of
+/// dumping the raw bytes. `bincode` isn't self-describing, so the type is
+/// picked deterministically from `output_path` rather than by trying
+/// candidates and keeping whichever happens to parse — the latter can
+/// silently "succeed" against the wrong type and print a bogus summary.
+fn decode_binary_payload(output_path: &str, data: &[u8]) -> Result<String> {
+    if output_path.ends_with("line_data") {
+        let line_data: LineData = bincode::deserialize(data)?;
+        Ok(format!(
+            "LineData {{ lines_in_robot: {}, used_vertical_filtered_segments: {} }}",
+            line_data.lines_in_robot.len(),
+            line_data.used_vertical_filtered_segments.len(),
+        ))
+    } else if output_path.ends_with("image_lines") {
+        let image_lines: ImageLines = bincode::deserialize(data)?;
+        Ok(format!(
+            "ImageLines {{ lines: {}, points: {} }}",
+            image_lines.lines.len(),
+            image_lines.points.len(),
+        ))
+    } else {
+        bail!(
+            "don't know how to decode binary output \"{output_path}\": expected a path ending \
+             in \"line_data\" or \"image_lines\""
+        )
     }
-    Ok(())
 }